@@ -0,0 +1,170 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+/// Bumped whenever the on-disk cache format changes (e.g. the Parquet
+/// writer settings). A mismatch wipes the cache directory so stale
+/// artifacts never get read as if they were current.
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+const CACHE_VERSION_FILE: &str = ".cache_version";
+
+/// Runtime configuration for where and how long downloaded microdata is
+/// cached, loaded from the environment (and a `.env` file, if present).
+///
+/// Recognized variables: `CACHE_DIR`, `MAX_CACHE_SIZE` (bytes), `MAX_AGE_H`
+/// (hours), `PARQUET_CACHE` (bool). Anything unset falls back to its
+/// default.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: PathBuf,
+    pub max_cache_size: Option<u64>,
+    pub max_age_h: Option<u64>,
+    #[serde(default)]
+    pub parquet_cache: bool,
+}
+
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from("./input")
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cache_dir: default_cache_dir(),
+            max_cache_size: None,
+            max_age_h: None,
+            parquet_cache: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Config> {
+        dotenvy::dotenv().ok();
+        Ok(envy::from_env::<Config>()?)
+    }
+
+    /// Whether the cached file at `path` is older than `max_age_h`, if
+    /// that's configured. Files are never stale when no TTL is set.
+    pub(crate) async fn is_stale(&self, path: &Path) -> anyhow::Result<bool> {
+        let Some(max_age_h) = self.max_age_h else {
+            return Ok(false);
+        };
+        let modified = fs::metadata(path).await?.modified()?;
+        Ok(Self::is_older_than(modified, max_age_h))
+    }
+
+    fn is_older_than(modified: SystemTime, max_age_h: u64) -> bool {
+        let age = modified.elapsed().unwrap_or_default();
+        age > Duration::from_secs(max_age_h * 3600)
+    }
+
+    /// Wipes `cache_dir` if its contents were written by an older cache
+    /// format, then stamps it with the current [`CACHE_FORMAT_VERSION`].
+    pub(crate) async fn invalidate_stale_cache(&self) -> anyhow::Result<()> {
+        let version_file = self.cache_dir.join(CACHE_VERSION_FILE);
+        let current_version = fs::read_to_string(&version_file)
+            .await
+            .ok()
+            .and_then(|s| s.trim().parse::<u32>().ok());
+
+        if current_version != Some(CACHE_FORMAT_VERSION) {
+            if fs::try_exists(&self.cache_dir).await? {
+                log::info!(
+                    "cache format changed, wiping stale cache at {}",
+                    self.cache_dir.display()
+                );
+                fs::remove_dir_all(&self.cache_dir).await?;
+            }
+            fs::create_dir_all(&self.cache_dir).await?;
+            fs::write(&version_file, CACHE_FORMAT_VERSION.to_string()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Evicts least-recently-used files from `cache_dir` until it's back
+    /// under `max_cache_size`, if that's configured.
+    pub(crate) async fn enforce_size_cap(&self) -> anyhow::Result<()> {
+        let Some(max_cache_size) = self.max_cache_size else {
+            return Ok(());
+        };
+
+        let mut read_dir = match fs::read_dir(&self.cache_dir).await {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = Vec::new();
+        while let Some(entry) = read_dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let last_used = metadata.accessed().or_else(|_| metadata.modified())?;
+            entries.push((entry.path(), metadata.len(), last_used));
+        }
+
+        for path in Self::evict_to_fit(entries, max_cache_size) {
+            log::debug!("evicting {} from cache (over size cap)", path.display());
+            fs::remove_file(&path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Given the cache's entries as `(path, size, last-used)`, picks which
+    /// ones to remove, least-recently-used first, to bring the total size
+    /// back to `max_size` or under.
+    fn evict_to_fit(mut entries: Vec<(PathBuf, u64, SystemTime)>, max_size: u64) -> Vec<PathBuf> {
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort_by_key(|(_, _, last_used)| *last_used);
+
+        let mut to_evict = Vec::new();
+        for (path, len, _) in entries {
+            if total <= max_size {
+                break;
+            }
+            to_evict.push(path);
+            total = total.saturating_sub(len);
+        }
+        to_evict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_older_than_respects_ttl() {
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 3600);
+        assert!(Config::is_older_than(two_hours_ago, 1));
+        assert!(!Config::is_older_than(two_hours_ago, 3));
+    }
+
+    #[test]
+    fn evict_to_fit_removes_least_recently_used_until_under_cap() {
+        let base = SystemTime::UNIX_EPOCH;
+        let entries = vec![
+            (PathBuf::from("a"), 100, base + Duration::from_secs(1)),
+            (PathBuf::from("b"), 100, base + Duration::from_secs(2)),
+            (PathBuf::from("c"), 100, base + Duration::from_secs(3)),
+        ];
+
+        let evicted = Config::evict_to_fit(entries, 150);
+
+        assert_eq!(evicted, vec![PathBuf::from("a"), PathBuf::from("b")]);
+    }
+
+    #[test]
+    fn evict_to_fit_is_noop_when_already_under_cap() {
+        let entries = vec![(PathBuf::from("a"), 50, SystemTime::UNIX_EPOCH)];
+
+        assert!(Config::evict_to_fit(entries, 100).is_empty());
+    }
+}
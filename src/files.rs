@@ -1,16 +1,54 @@
-use polars::prelude::{LazyCsvReader, LazyFileListReader, LazyFrame, PolarsError};
-use reqwest::ClientBuilder;
-use std::io::{Cursor, Read};
-use std::path::Path;
+use crate::config::Config;
+use async_zip::tokio::read::fs::ZipFileReader;
+use encoding_rs::{CoderResult, Decoder, Encoding, WINDOWS_1252};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use polars::prelude::{
+    LazyCsvReader, LazyFileListReader, LazyFrame, ParquetCompression, ParquetWriter, PolarsError,
+    ScanArgsParquet, ZstdLevel,
+};
+use reqwest::header::RANGE;
+use reqwest::{ClientBuilder, StatusCode};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use tokio::fs;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::sync::OnceCell;
 use tokio::task::JoinSet;
-use zip::ZipArchive;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
 
 const MICRODATA_PREFIX: &str = "MICRODADOS_CADASTRO";
 
+/// Process-wide [`Config`], loaded from the environment on first use.
+fn config() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        Config::load().unwrap_or_else(|e| {
+            log::warn!("failed to load config, falling back to defaults: {e}");
+            Config::default()
+        })
+    })
+}
+
+/// Wipes the cache if its format is stale and evicts over-cap entries,
+/// exactly once per process no matter how many callers race to trigger it —
+/// concurrent `ensure_data`/`ensure_all_data` calls must never run these
+/// against each other's in-flight downloads.
+async fn enforce_cache_invariants_once() -> anyhow::Result<()> {
+    static DONE: OnceCell<()> = OnceCell::const_new();
+    DONE.get_or_try_init(|| async {
+        config().invalidate_stale_cache().await?;
+        config().enforce_size_cap().await?;
+        anyhow::Ok(())
+    })
+    .await?;
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Ces {
     year: u16,
+    config: &'static Config,
 }
 
 impl Ces {
@@ -29,7 +67,10 @@ impl Ces {
         if year < 2009 {
             panic!("Years before 2009 are not suppported in this software yet.")
         } else {
-            Ces { year }
+            Ces {
+                year,
+                config: config(),
+            }
         }
     }
 
@@ -39,8 +80,16 @@ impl Ces {
 }
 
 impl Ces {
-    pub fn lf_cursos(&self) -> Result<LazyFrame, PolarsError> {
-        LazyCsvReader::new(&self.path(Microdata::Cursos))
+    /// Lazily reads the cached data for the given `kind` of microdata,
+    /// preferring the Parquet artifact when one was cached and falling
+    /// back to the plain csv otherwise.
+    pub fn lf(&self, kind: Microdata) -> Result<LazyFrame, PolarsError> {
+        let parquet_path = self.parquet_path(kind);
+        if parquet_path.try_exists().unwrap_or(false) {
+            return LazyFrame::scan_parquet(parquet_path, ScanArgsParquet::default());
+        }
+
+        LazyCsvReader::new(&self.path(kind))
             .with_delimiter(b';')
             .finish()
     }
@@ -48,12 +97,19 @@ impl Ces {
 
 impl Ces {
     /// Ensures that all input data is downloaded.
+    ///
+    /// Every year downloads concurrently, each with its own progress bar
+    /// under a shared [`MultiProgress`].
     pub async fn ensure_all_data() -> anyhow::Result<()> {
+        enforce_cache_invariants_once().await?;
+
         let mut set = JoinSet::new();
+        let multi = MultiProgress::new();
 
         for year in 2009..2022 {
+            let multi = multi.clone();
             set.spawn(async move {
-                Ces::new(year).ensure_data().await.unwrap();
+                Ces::new(year).ensure_data_with(&multi).await.unwrap();
             });
         }
 
@@ -71,35 +127,75 @@ impl Ces {
     }
 
     /// Ensures that input data for this Ces is downloaded.
+    ///
+    /// Safe to call concurrently, including from multiple `Ces` instances
+    /// at once (e.g. one call per year): the cache's format-version and
+    /// size invariants are only ever enforced once per process, via
+    /// [`enforce_cache_invariants_once`].
     pub async fn ensure_data(&self) -> anyhow::Result<()> {
+        enforce_cache_invariants_once().await?;
+        self.ensure_data_with(&MultiProgress::new()).await
+    }
+
+    /// Runs the download/extraction for a single year. Assumes the cache's
+    /// format-version and size invariants have already been enforced for
+    /// this process, so it doesn't touch them itself — doing so here would
+    /// race the concurrent per-year tasks [`Ces::ensure_all_data`] spawns
+    /// against each other's in-flight downloads.
+    async fn ensure_data_with(&self, multi: &MultiProgress) -> anyhow::Result<()> {
         if self.already_downloaded().await? {
             log::debug!("[{}] data already exists.", self.year());
         } else {
             log::debug!("[{}]", self.year());
-            self.download_data().await?;
+            self.download_data(multi).await?;
         }
         Ok(())
     }
 
+    /// Uses `Cursos` as the canonical table: every year's archive has it,
+    /// and it's extracted alongside every other table in the same pass.
+    /// A cached file that has exceeded the configured TTL is treated as if
+    /// it weren't downloaded, so it gets refreshed.
     async fn already_downloaded(&self) -> anyhow::Result<bool> {
-        let path_string = self.path(Microdata::Cursos);
-        Ok(Path::new(&path_string).try_exists()?)
+        let path = self.path(Microdata::Cursos);
+        if !path.try_exists()? {
+            return Ok(false);
+        }
+        Ok(!self.config.is_stale(&path).await?)
     }
 
-    fn path(&self, kind: Microdata) -> String {
-        match kind {
-            Microdata::Cursos => format!("input/cursos.{}.csv", self.year()),
-        }
+    fn path(&self, kind: Microdata) -> PathBuf {
+        self.config
+            .cache_dir
+            .join(format!("{}.{}.csv", kind.slug(), self.year()))
+    }
+
+    fn parquet_path(&self, kind: Microdata) -> PathBuf {
+        self.config
+            .cache_dir
+            .join(format!("{}.{}.parquet", kind.slug(), self.year()))
     }
 
-    async fn download_data(self) -> anyhow::Result<()> {
-        let zip = self.zip().await?;
+    /// Where a table's decoded bytes are staged while its MD5 is still
+    /// being verified, before they're promoted to [`Ces::path`].
+    fn tmp_path(&self, kind: Microdata) -> PathBuf {
+        self.config
+            .cache_dir
+            .join(format!("{}.{}.csv.tmp", kind.slug(), self.year()))
+    }
+
+    fn zip_path(&self) -> PathBuf {
+        self.config
+            .cache_dir
+            .join(format!("cursos.{}.zip", self.year()))
+    }
 
-        log::debug!("[{}] extracting zip files...", self.year());
-        let cursos =
-            tokio::task::spawn_blocking(move || self.extract_cursos_microdata(zip).unwrap())
-                .await?;
-        self.save_cursos(cursos).await?;
+    async fn download_data(self, multi: &MultiProgress) -> anyhow::Result<()> {
+        let zip_path = self.download_zip(multi).await?;
+
+        log::debug!("[{}] extracting zip file...", self.year());
+        self.extract_all_microdata(&zip_path).await?;
+        fs::remove_file(&zip_path).await?;
 
         log::info!("[{}] data downloaded!", self.year());
         Ok(())
@@ -112,74 +208,298 @@ impl Ces {
         )
     }
 
-    async fn zip(&self) -> reqwest::Result<Vec<u8>> {
+    /// Streams the year's zip archive straight to disk, so the whole
+    /// (multi-gigabyte) download is never buffered in memory at once.
+    ///
+    /// If a partial download is already sitting in the cache, resumes it
+    /// with a `Range` request instead of restarting from scratch. Progress
+    /// is reported on its own line of `multi`.
+    async fn download_zip(&self, multi: &MultiProgress) -> anyhow::Result<PathBuf> {
         // TODO: find a way to store the server's certificate here, because
         // it seems that when downloading the file it doesn't send the certificate
         log::debug!("[{}] Sending request to {}", self.year, self.url());
         let client = ClientBuilder::new()
             .danger_accept_invalid_certs(true)
             .build()?;
-        Ok(client.get(self.url()).send().await?.bytes().await?.to_vec())
-    }
-
-    fn extract_cursos_microdata(&self, zip: Vec<u8>) -> anyhow::Result<String> {
-        log::trace!("extract_cursos_microdata");
-        let mut archive = ZipArchive::new(Cursor::new(zip))?;
-        let names: Vec<String> = archive
-            .file_names()
-            .filter(|name| name.contains(MICRODATA_PREFIX))
-            .filter(|name| name.contains(Microdata::Cursos.name()))
-            .map(String::from)
-            .collect();
-
-        for name in names {
-            let mut zip_file = archive
-                .by_name(&name)
-                .expect("Using name provided by `file_names`");
-            let mut bytes = Vec::new();
-            zip_file.read_to_end(&mut bytes)?;
-
-            let clone = bytes.clone();
-            let downloaded_md5 = format!("{:x}", md5::compute(clone));
-            let correct_md5 = Microdata::Cursos.original_md5(self.year()).unwrap();
-            if downloaded_md5 != correct_md5 {
-                dbg!(self.year(), downloaded_md5, correct_md5);
-                anyhow::bail!("[{}], wrong md5", self.year());
-            } else {
-                log::debug!("[{}] correct md5", self.year());
+
+        let zip_path = self.zip_path();
+        fs::create_dir_all(zip_path.parent().expect("zip_path has a parent")).await?;
+
+        let mut resume_from = fs::metadata(&zip_path).await.map(|m| m.len()).unwrap_or(0);
+        let mut request = client.get(self.url());
+        if resume_from > 0 {
+            request = request.header(RANGE, format!("bytes={resume_from}-"));
+        }
+        let mut response = request.send().await?;
+
+        // The cached zip can already hold the full file (e.g. a previous run
+        // failed during extraction), in which case the server has nothing
+        // left to send for our range and answers 416. Fall back to
+        // restarting the download from scratch instead of treating that as
+        // a hard failure.
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            log::warn!(
+                "[{}] cached zip is incompatible with the server's range, restarting download",
+                self.year()
+            );
+            fs::remove_file(&zip_path).await?;
+            resume_from = 0;
+            response = client.get(self.url()).send().await?.error_for_status()?;
+        } else {
+            response = response.error_for_status()?;
+        }
+        let resuming = resume_from > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+
+        let total = resume_from + response.content_length().unwrap_or(0);
+        let pb = multi.add(ProgressBar::new(total));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{prefix}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({eta})",
+            )
+            .expect("valid progress bar template"),
+        );
+        pb.set_prefix(self.year().to_string());
+        pb.set_position(resume_from);
+
+        let mut file = BufWriter::new(if resuming {
+            OpenOptions::new().append(true).open(&zip_path).await?
+        } else {
+            File::create(&zip_path).await?
+        });
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            pb.inc(chunk.len() as u64);
+        }
+        file.flush().await?;
+        pb.finish();
+
+        Ok(zip_path)
+    }
+
+    /// Walks every member of the zip at `zip_path` once, extracting each one
+    /// that matches a known [`Microdata`] table into its own cached csv file.
+    /// Tables that a given year's archive doesn't include are skipped.
+    async fn extract_all_microdata(&self, zip_path: &Path) -> anyhow::Result<()> {
+        log::trace!("extract_all_microdata");
+        let zip = ZipFileReader::new(zip_path).await?;
+        fs::create_dir_all(&self.config.cache_dir).await?;
+
+        for index in 0..zip.file().entries().len() {
+            let filename = zip.file().entries()[index]
+                .filename()
+                .as_str()
+                .unwrap_or_default();
+            let Some(kind) = Microdata::from_filename(filename) else {
+                continue;
+            };
+            self.extract_microdata(&zip, index, kind).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Streams the decompressed, decoded bytes of the zip entry at `index`
+    /// into a temporary file, without ever holding the whole member's
+    /// contents in memory, and only promotes it to `kind`'s cached csv file
+    /// once its MD5 has been verified — a corrupted download must never
+    /// leave a validated-looking file behind. Tables with no known MD5 are
+    /// promoted without an integrity check.
+    async fn extract_microdata(
+        &self,
+        zip: &ZipFileReader,
+        index: usize,
+        kind: Microdata,
+    ) -> anyhow::Result<()> {
+        let mut entry_reader = zip.reader_with_entry(index).await?.compat();
+        let tmp_path = self.tmp_path(kind);
+        let mut out = BufWriter::new(File::create(&tmp_path).await?);
+
+        let mut md5_context = md5::Context::new();
+        let mut decoder: Option<Decoder> = None;
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = entry_reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
             }
+            md5_context.consume(&buf[..n]);
+
+            let mut chunk = &buf[..n];
+            let declared_encoding = kind.encoding(self.year());
+            let decoder =
+                decoder.get_or_insert_with(|| decoder_for_first_chunk(&mut chunk, declared_encoding));
 
-            let string = iso_8559_1_to_utf8(bytes);
-            return Ok(string);
+            decode_all(decoder, chunk, false, &mut out).await?;
         }
-        anyhow::bail!("Cursos microdata wasn't found");
+        if let Some(mut decoder) = decoder {
+            decode_all(&mut decoder, &[], true, &mut out).await?;
+        }
+        out.flush().await?;
+        drop(out);
+
+        match kind.original_md5(self.year()) {
+            Some(correct_md5) => {
+                let downloaded_md5 = format!("{:x}", md5_context.compute());
+                if downloaded_md5 != correct_md5 {
+                    dbg!(self.year(), kind, downloaded_md5, correct_md5);
+                    fs::remove_file(&tmp_path).await?;
+                    anyhow::bail!("[{}] {:?}, wrong md5", self.year(), kind);
+                } else {
+                    log::debug!("[{}] {:?} correct md5", self.year(), kind);
+                }
+            }
+            None => log::warn!(
+                "[{}] no known md5 for {:?}, skipping integrity check",
+                self.year(),
+                kind
+            ),
+        }
+
+        fs::rename(&tmp_path, self.path(kind)).await?;
+
+        if self.config.parquet_cache {
+            self.write_parquet_cache(kind).await?;
+        }
+
+        Ok(())
     }
 
-    async fn save_cursos(&self, s: String) -> std::io::Result<()> {
-        let input = Path::new("./input");
-        let filename = format!("cursos.{}.csv", self.year());
-        fs::create_dir_all(&input).await?;
-        fs::write(&input.join(filename), s).await
+    /// Transcodes the just-extracted csv into a Zstd-compressed Parquet
+    /// file, which `lf` then prefers for faster, smaller-on-disk reads.
+    async fn write_parquet_cache(&self, kind: Microdata) -> anyhow::Result<()> {
+        let csv_path = self.path(kind);
+        let parquet_path = self.parquet_path(kind);
+
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut df = LazyCsvReader::new(&csv_path)
+                .with_delimiter(b';')
+                .finish()?
+                .collect()?;
+
+            let file = std::fs::File::create(&parquet_path)?;
+            ParquetWriter::new(file)
+                .with_compression(ParquetCompression::Zstd(Some(ZstdLevel::try_new(3)?)))
+                .finish(&mut df)?;
+
+            Ok(())
+        })
+        .await??;
+
+        Ok(())
     }
 }
 
-/// Converts a file encoded in ISO-8559-1 to UTF-8 encoding,
-/// which is the encoding of Rust strings.
-fn iso_8559_1_to_utf8(bytes: Vec<u8>) -> String {
-    bytes.iter().map(|&c| c as char).collect()
+/// Picks the decoder for the first chunk of an entry: a BOM, if present,
+/// overrides `declared` and is stripped from `chunk`; otherwise `declared`
+/// is used as-is.
+fn decoder_for_first_chunk(chunk: &mut &[u8], declared: &'static Encoding) -> Decoder {
+    let encoding = match Encoding::for_bom(chunk) {
+        Some((bom_encoding, bom_len)) => {
+            *chunk = &chunk[bom_len..];
+            bom_encoding
+        }
+        None => declared,
+    };
+    encoding.new_decoder_without_bom_handling()
 }
 
-enum Microdata {
+/// Feeds `src` through `decoder` and writes the decoded text to `out`.
+///
+/// `Decoder::decode_to_string` only fills `dst`'s existing spare capacity
+/// and reports `OutputFull` without consuming the rest of `src` if that
+/// isn't enough room — it does not grow `dst` itself. So this loops,
+/// growing the buffer between calls, until the decoder reports the whole
+/// of `src` was consumed.
+async fn decode_all(
+    decoder: &mut Decoder,
+    mut src: &[u8],
+    last: bool,
+    out: &mut BufWriter<File>,
+) -> anyhow::Result<()> {
+    let mut capacity = src.len() * 3 + 1;
+    loop {
+        let mut decoded = String::with_capacity(capacity);
+        let (result, consumed, _) = decoder.decode_to_string(src, &mut decoded, last);
+        out.write_all(decoded.as_bytes()).await?;
+        src = &src[consumed..];
+        match result {
+            CoderResult::InputEmpty => return Ok(()),
+            CoderResult::OutputFull => capacity *= 2,
+        }
+    }
+}
+
+/// One of the per-year tables that make up the Censo da Educação Superior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Microdata {
     Cursos,
+    Ies,
+    Aluno,
+    Docente,
+    LocalOferta,
 }
 
 impl Microdata {
+    /// Every table this crate knows how to extract.
+    pub fn all() -> Vec<Microdata> {
+        vec![
+            Self::Cursos,
+            Self::Ies,
+            Self::Aluno,
+            Self::Docente,
+            Self::LocalOferta,
+        ]
+    }
+
+    /// Identifies which table, if any, a zip entry's filename belongs to.
+    ///
+    /// Checked in order from most to least specific, since e.g. the
+    /// `Cursos` token is itself a substring of some `LocalOferta` filenames.
+    fn from_filename(filename: &str) -> Option<Self> {
+        if !filename.contains(MICRODATA_PREFIX) {
+            return None;
+        }
+        [
+            Self::LocalOferta,
+            Self::Docente,
+            Self::Aluno,
+            Self::Ies,
+            Self::Cursos,
+        ]
+        .into_iter()
+        .find(|kind| filename.contains(kind.name()))
+    }
+
+    /// The token identifying this table in INEP's zip entry filenames.
     fn name(&self) -> &str {
         match self {
             Self::Cursos => "CURSOS",
+            Self::Ies => "IES",
+            Self::Aluno => "ALUNO",
+            Self::Docente => "DOCENTE",
+            Self::LocalOferta => "LOCAL_OFERTA",
         }
     }
 
+    /// The token used in this crate's own cache filenames.
+    fn slug(&self) -> &str {
+        match self {
+            Self::Cursos => "cursos",
+            Self::Ies => "ies",
+            Self::Aluno => "aluno",
+            Self::Docente => "docente",
+            Self::LocalOferta => "local_oferta",
+        }
+    }
+
+    /// The encoding INEP used for this table in the given year, absent a
+    /// BOM saying otherwise. Defaults to WINDOWS-1252, a superset of
+    /// ISO-8859-1 for the bytes INEP uses; years with a different encoding
+    /// can be special-cased here without touching the extraction code.
+    fn encoding(&self, _year: u16) -> &'static Encoding {
+        WINDOWS_1252
+    }
+
     fn original_md5(&self, year: u16) -> Option<&str> {
         match self {
             Self::Cursos => match year {
@@ -198,6 +518,9 @@ impl Microdata {
                 2021 => Some("05d78ff911cea316cd65f08b0e93e83d"),
                 _ => None,
             },
+            // MD5s for the other tables haven't been catalogued yet; their
+            // integrity check is skipped until they are.
+            _ => None,
         }
     }
 }
@@ -227,4 +550,42 @@ mod tests {
             "https://download.inep.gov.br/microdados/microdados_censo_da_educacao_superior_2011.zip",
         )
     }
+
+    #[test]
+    fn from_filename_prefers_more_specific_token() {
+        // LOCAL_OFERTA filenames also contain CURSOS; the more specific
+        // token must win.
+        let filename = "MICRODADOS_CADASTRO_LOCAL_OFERTA_CURSOS_2021.CSV";
+        assert_eq!(
+            Microdata::from_filename(filename),
+            Some(Microdata::LocalOferta)
+        );
+    }
+
+    #[test]
+    fn from_filename_matches_plain_cursos() {
+        let filename = "MICRODADOS_CADASTRO_CURSOS_2021.CSV";
+        assert_eq!(Microdata::from_filename(filename), Some(Microdata::Cursos));
+    }
+
+    #[test]
+    fn from_filename_without_microdata_prefix_is_none() {
+        assert_eq!(Microdata::from_filename("LEIA_ME.txt"), None);
+    }
+
+    #[test]
+    fn decoder_for_first_chunk_prefers_bom_encoding_and_strips_it() {
+        let mut chunk: &[u8] = b"\xef\xbb\xbfhello";
+        let decoder = decoder_for_first_chunk(&mut chunk, WINDOWS_1252);
+        assert_eq!(decoder.encoding(), encoding_rs::UTF_8);
+        assert_eq!(chunk, b"hello");
+    }
+
+    #[test]
+    fn decoder_for_first_chunk_falls_back_to_declared_without_bom() {
+        let mut chunk: &[u8] = b"hello";
+        let decoder = decoder_for_first_chunk(&mut chunk, WINDOWS_1252);
+        assert_eq!(decoder.encoding(), WINDOWS_1252);
+        assert_eq!(chunk, b"hello");
+    }
 }
@@ -1,3 +1,4 @@
+mod config;
 mod files;
 
 use files::Ces;